@@ -17,8 +17,8 @@
 #[cfg(not(feature = "std"))]
 use rstd::prelude::*;
 use rstd::{borrow::Borrow, iter::FromIterator};
-use codec::{Codec, Encode};
-use crate::{storage::{self, unhashed, hashed::{Twox128, StorageHasher}}, traits::Len};
+use codec::{Codec, Decode, Encode};
+use crate::{storage::{self, unhashed, hashed::{Twox128, StorageHasher}}, traits::{Get, Len}};
 
 /// Generator for `StorageValue` used by `decl_storage`.
 ///
@@ -30,14 +30,27 @@ pub trait StorageValue<T: Codec> {
 	/// The type that get/take returns.
 	type Query;
 
+	/// Query behaviour (`OptionQuery` or `ValueQuery`) that the default
+	/// `from_optional_value_to_query`/`from_query_to_optional_value` pair below is derived from.
+	/// `decl_storage`/macro output picks this declaratively instead of hand-writing the
+	/// conversion bodies; a fully custom generator can still override those two methods directly.
+	type QueryKind: QueryKindTrait<T, Self::OnEmpty, Query = Self::Query>;
+
+	/// Default value provider consulted when `QueryKind = ValueQuery`; ignored by `OptionQuery`.
+	type OnEmpty;
+
 	/// Unhashed key used in storage
 	fn unhashed_key() -> &'static [u8];
 
 	/// Convert an optional value retrieved from storage to the type queried.
-	fn from_optional_value_to_query(v: Option<T>) -> Self::Query;
+	fn from_optional_value_to_query(v: Option<T>) -> Self::Query {
+		Self::QueryKind::from_optional_value_to_query(v)
+	}
 
 	/// Convert a query to an optional value into storage.
-	fn from_query_to_optional_value(v: Self::Query) -> Option<T>;
+	fn from_query_to_optional_value(v: Self::Query) -> Option<T> {
+		Self::QueryKind::from_query_to_optional_value(v)
+	}
 
 	/// Generate the full key used in top storage.
 	fn storage_value_final_key() -> [u8; 16] {
@@ -45,6 +58,55 @@ pub trait StorageValue<T: Codec> {
 	}
 }
 
+/// Type-level choice of query behaviour for a `StorageValue`: what `Self::Query` an absent raw
+/// value should be converted to, and how to convert it back when writing.
+///
+/// Implemented by [`OptionQuery`] and [`ValueQuery`], so that `decl_storage`/macro output can pick
+/// query behaviour declaratively instead of emitting a bespoke
+/// `from_optional_value_to_query`/`from_query_to_optional_value` pair per storage item.
+pub trait QueryKindTrait<Value, OnEmpty> {
+	/// The type that `get`/`take` returns.
+	type Query;
+
+	/// Convert an optional value retrieved from storage to the type queried.
+	fn from_optional_value_to_query(v: Option<Value>) -> Self::Query;
+
+	/// Convert a query to an optional value into storage.
+	fn from_query_to_optional_value(v: Self::Query) -> Option<Value>;
+}
+
+/// `QueryKind` for a `StorageValue` whose absence is represented as `None`, distinct from any
+/// value the type could hold. `OnEmpty` is unused and fixed to `()`.
+pub struct OptionQuery;
+
+impl<Value> QueryKindTrait<Value, ()> for OptionQuery {
+	type Query = Option<Value>;
+
+	fn from_optional_value_to_query(v: Option<Value>) -> Self::Query {
+		v
+	}
+
+	fn from_query_to_optional_value(v: Self::Query) -> Option<Value> {
+		v
+	}
+}
+
+/// `QueryKind` for a `StorageValue` whose absence is collapsed into the default supplied by
+/// `OnEmpty: Get<Value>`, so callers never have to handle `None`.
+pub struct ValueQuery;
+
+impl<Value, OnEmpty: Get<Value>> QueryKindTrait<Value, OnEmpty> for ValueQuery {
+	type Query = Value;
+
+	fn from_optional_value_to_query(v: Option<Value>) -> Self::Query {
+		v.unwrap_or_else(OnEmpty::get)
+	}
+
+	fn from_query_to_optional_value(v: Self::Query) -> Option<Value> {
+		Some(v)
+	}
+}
+
 impl<T: Codec, G: StorageValue<T>> storage::StorageValue<T> for G {
 	type Query = G::Query;
 
@@ -93,9 +155,66 @@ impl<T: Codec, G: StorageValue<T>> storage::StorageValue<T> for G {
 		G::from_optional_value_to_query(value)
 	}
 
+	/// Try to get the value, returning `Err(())` if there is no raw encoded value at the key,
+	/// rather than falling back to the `OptionQuery`/`ValueQuery` default used by `get`.
+	fn try_get() -> Result<T, ()> {
+		unhashed::get(&Self::storage_value_final_key()).ok_or(())
+	}
+
+	/// Like `mutate`, but only writes the mutated query back (via `put`/`kill`) when `f` returns
+	/// `Ok`. Storage is left untouched if `f` returns `Err`.
+	fn try_mutate<R, E, F: FnOnce(&mut G::Query) -> Result<R, E>>(f: F) -> Result<R, E> {
+		let mut val = G::get();
+
+		let ret = f(&mut val);
+		if ret.is_ok() {
+			match G::from_query_to_optional_value(val) {
+				Some(ref val) => G::put(val),
+				None => G::kill(),
+			}
+		}
+		ret
+	}
+
+	/// Translate a value from some previous type (`O`) to the current type.
+	///
+	/// `f: F` is the translation function. It is called with the decoded old value, or with `None`
+	/// if there is no value at the key. If the old value fails to decode as `O`, `f` is not called,
+	/// `Err(())` is returned and storage is left untouched, giving the caller a chance to handle
+	/// the corruption rather than silently losing data. Otherwise the translated value returned
+	/// by `f` is `put` (or `kill`ed if `None`), and also returned for convenience.
+	fn translate<O: Decode, F: FnOnce(Option<O>) -> Option<T>>(f: F) -> Result<Option<T>, ()> {
+		let key = Self::storage_value_final_key();
+
+		let old = match unhashed::get_raw(&key) {
+			Some(old_data) => Some(O::decode(&mut &old_data[..]).map_err(|_| ())?),
+			None => None,
+		};
+		let new = f(old);
+
+		match &new {
+			Some(new) => unhashed::put(&key, new),
+			None => unhashed::kill(&key),
+		}
+
+		Ok(new)
+	}
+
 	/// Append the given items to the value in the storage.
 	///
 	/// `T` is required to implement `codec::EncodeAppend`.
+	///
+	/// NOT AMORTIZED: this is still a full `unhashed::get_raw` + re-encode + `put_raw` per call,
+	/// i.e. O(n) per append and O(n²) total for n appends to the same key within a block. A
+	/// per-key cache of the encoded buffer was attempted here to avoid that, but a process-global
+	/// cache keyed only on the generic storage-item type `G` cannot tell two distinct storage
+	/// items (e.g. two different pallets' values) apart, since a `static` declared inside a
+	/// generic function is not duplicated per monomorphization — it is one instance shared by
+	/// every `G` — and was reverted for correctness. Caching this safely needs to be keyed by the
+	/// actual storage key and scoped to the storage transaction/overlay (so it is invalidated by
+	/// transaction rollbacks and fresh externalities, not just by this generator's own accessors);
+	/// that belongs in the storage overlay itself, which this module has no access to. Deferred:
+	/// the amortized version from the original request is not implemented here.
 	fn append<'a, I, R>(items: R) -> Result<(), &'static str>
 	where
 		I: 'a + codec::Encode,
@@ -156,4 +275,206 @@ impl<T: Codec, G: StorageValue<T>> storage::StorageValue<T> for G {
 			Ok(len)
 		}
 	}
+
+	/// Read the non-dedup length of the value by decoding it in full, for collections that,
+	/// unlike `Vec`, do not keep every encoded element they were ever given: the raw length
+	/// prefix written by a non-deduplicating `append` can over-count, since it is incremented for
+	/// every appended element regardless of whether it ended up being folded into an existing one
+	/// once decoded. `T` is required to implement `StorageDecodeNonDedupLength`.
+	fn decode_non_dedup_len() -> Result<usize, &'static str> where T: StorageDecodeNonDedupLength, T: Len {
+		let key = Self::storage_value_final_key();
+
+		if let Some(k) = unhashed::get_raw(&key) {
+			<T as StorageDecodeNonDedupLength>::len(&k)
+		} else {
+			let len = G::from_query_to_optional_value(G::from_optional_value_to_query(None))
+				.map(|v| v.len())
+				.unwrap_or(0);
+
+			Ok(len)
+		}
+	}
+}
+
+/// Trait for collections, such as `BTreeSet`, whose raw compact length prefix does not
+/// equal their decoded cardinality because the encoded representation can contain entries that
+/// collapse into one another once decoded (e.g. duplicate inserts appended over several blocks). Unlike `codec::DecodeLength`, which just reads the length prefix, this always
+/// decodes the value so that the reported length matches `Len::len()` on the decoded collection.
+pub trait StorageDecodeNonDedupLength: Decode {
+	/// Decode the length of the non-dedup-encoded value, given the SCALE-encoded bytes.
+	fn len(self_encoded: &[u8]) -> Result<usize, &'static str>;
+}
+
+impl<T: Decode + Ord> StorageDecodeNonDedupLength for rstd::collections::btree_set::BTreeSet<T> {
+	fn len(mut self_encoded: &[u8]) -> Result<usize, &'static str> {
+		Self::decode(&mut self_encoded)
+			.map(|set| set.len())
+			.map_err(|_| "failed to decode BTreeSet for non-dedup length")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use runtime_io::{with_externalities, TestExternalities};
+
+	struct Value;
+	impl StorageValue<u32> for Value {
+		type Query = Option<u32>;
+		type QueryKind = OptionQuery;
+		type OnEmpty = ();
+
+		fn unhashed_key() -> &'static [u8] {
+			b"Value"
+		}
+	}
+
+	#[test]
+	fn try_get_returns_err_only_when_absent() {
+		with_externalities(&mut TestExternalities::default(), || {
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Err(()));
+
+			Value::put(&1u32);
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(1));
+
+			Value::kill();
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Err(()));
+		});
+	}
+
+	#[test]
+	fn try_mutate_only_writes_back_on_ok() {
+		with_externalities(&mut TestExternalities::default(), || {
+			Value::put(&1u32);
+
+			let res = Value::try_mutate(|v| -> Result<(), &'static str> {
+				*v = Some(2);
+				Err("nope")
+			});
+			assert_eq!(res, Err("nope"));
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(1));
+
+			let res = Value::try_mutate(|v| -> Result<(), &'static str> {
+				*v = Some(3);
+				Ok(())
+			});
+			assert_eq!(res, Ok(()));
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(3));
+		});
+	}
+
+	#[test]
+	fn translate_decodes_the_old_type_and_stores_the_new_one() {
+		with_externalities(&mut TestExternalities::default(), || {
+			// Absent key: `f` is called with `None`, and whatever it returns is stored as-is.
+			let res = Value::translate(|old: Option<u8>| old.map(|v| v as u32).or(Some(9)));
+			assert_eq!(res, Ok(Some(9)));
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(9));
+
+			// A real migration: the old bytes decode as `u8`, widened here into the new `u32`.
+			Value::kill();
+			unhashed::put(&Value::storage_value_final_key(), &7u8);
+			let res = Value::translate(|old: Option<u8>| old.map(|v| v as u32 * 10));
+			assert_eq!(res, Ok(Some(70)));
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(70));
+		});
+	}
+
+	#[test]
+	fn translate_leaves_storage_untouched_on_old_type_decode_failure() {
+		with_externalities(&mut TestExternalities::default(), || {
+			Value::put(&1u32);
+
+			// `[u8; 40]` can never decode from the 4 bytes a `u32` encodes to.
+			let res = Value::translate(|_: Option<[u8; 40]>| Some(0u32));
+			assert_eq!(res, Err(()));
+			assert_eq!(<Value as storage::StorageValue<u32>>::try_get(), Ok(1));
+		});
+	}
+
+	struct EmptySet;
+	impl Get<rstd::collections::btree_set::BTreeSet<u32>> for EmptySet {
+		fn get() -> rstd::collections::btree_set::BTreeSet<u32> {
+			Default::default()
+		}
+	}
+
+	struct SetValue;
+	impl StorageValue<rstd::collections::btree_set::BTreeSet<u32>> for SetValue {
+		type Query = rstd::collections::btree_set::BTreeSet<u32>;
+		type QueryKind = ValueQuery;
+		type OnEmpty = EmptySet;
+
+		fn unhashed_key() -> &'static [u8] {
+			b"SetValue"
+		}
+	}
+
+	#[test]
+	fn decode_non_dedup_len_matches_decoded_cardinality() {
+		with_externalities(&mut TestExternalities::default(), || {
+			assert_eq!(SetValue::decode_non_dedup_len(), Ok(0));
+
+			let mut set = rstd::collections::btree_set::BTreeSet::new();
+			set.insert(1u32);
+			set.insert(2u32);
+			SetValue::put(&set);
+
+			assert_eq!(SetValue::decode_non_dedup_len(), Ok(2));
+
+			// What a non-dedup `append` can actually produce: a raw compact length prefix of 3,
+			// as if three elements had been appended, but only 2 distinct values once decoded
+			// because `5u32` was written twice. `codec::DecodeLength` would over-report 3 here;
+			// `decode_non_dedup_len` decodes fully and must still report the real cardinality.
+			let mut raw = codec::Compact(3u32).encode();
+			raw.extend(5u32.encode());
+			raw.extend(5u32.encode());
+			raw.extend(6u32.encode());
+			unhashed::put_raw(&SetValue::storage_value_final_key(), &raw);
+
+			assert_eq!(SetValue::decode_non_dedup_len(), Ok(2));
+		});
+	}
+
+	struct EmptyVec;
+	impl Get<Vec<u32>> for EmptyVec {
+		fn get() -> Vec<u32> {
+			Vec::new()
+		}
+	}
+
+	struct ListA;
+	impl StorageValue<Vec<u32>> for ListA {
+		type Query = Vec<u32>;
+		type QueryKind = ValueQuery;
+		type OnEmpty = EmptyVec;
+
+		fn unhashed_key() -> &'static [u8] {
+			b"ListA"
+		}
+	}
+
+	struct ListB;
+	impl StorageValue<Vec<u32>> for ListB {
+		type Query = Vec<u32>;
+		type QueryKind = ValueQuery;
+		type OnEmpty = EmptyVec;
+
+		fn unhashed_key() -> &'static [u8] {
+			b"ListB"
+		}
+	}
+
+	#[test]
+	fn append_to_interleaved_distinct_keys_does_not_mix_them_up() {
+		with_externalities(&mut TestExternalities::default(), || {
+			ListA::append(&[1u32]).unwrap();
+			ListB::append(&[100u32]).unwrap();
+			ListA::append(&[2u32]).unwrap();
+			ListB::append(&[200u32]).unwrap();
+
+			assert_eq!(ListA::get(), vec![1, 2]);
+			assert_eq!(ListB::get(), vec![100, 200]);
+		});
+	}
 }